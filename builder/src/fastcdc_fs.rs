@@ -0,0 +1,131 @@
+// a streaming content-defined chunker: callers feed bytes in via `std::io::Write` (so
+// `io::copy(&mut file, &mut fcdc)` can drive it directly, as build_rootfs does for each file in
+// turn) and drain whatever chunks have closed so far with `get_pending_chunks`. because FastCDC
+// can only commit to a cut point once it's seen the bytes that follow it, the last candidate chunk
+// in the buffer is always held back until either more data arrives or `finish` is called to flush
+// it as-is.
+//
+// chunking runs over the concatenated byte stream of every file build_rootfs copies through it,
+// not per file, so a chunk boundary can (and for small files, usually does) span several files --
+// merge_chunk_and_prev_files in lib.rs is what splits a shared chunk back out per file afterwards.
+
+use std::io;
+
+use fastcdc::v2020::FastCDC;
+
+use crate::ChunkerConfig;
+
+pub(crate) struct ChunkWithData {
+    pub data: Vec<u8>,
+}
+
+pub(crate) struct FastCDCWrapper {
+    config: ChunkerConfig,
+    buf: Vec<u8>,
+    pending: Vec<ChunkWithData>,
+}
+
+impl FastCDCWrapper {
+    pub(crate) fn new(config: ChunkerConfig) -> Self {
+        Self {
+            config,
+            buf: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // re-cut whatever's in `buf`. on a non-final pass the last candidate cut is held back since it
+    // might still grow once more bytes are written; on the final pass (`finish`) everything left is
+    // flushed regardless.
+    fn cut(&mut self, final_pass: bool) {
+        let cuts: Vec<(usize, usize)> = FastCDC::new(
+            &self.buf,
+            self.config.min_size,
+            self.config.avg_size,
+            self.config.max_size,
+        )
+        .map(|c| (c.offset, c.length))
+        .collect();
+
+        let mut consumed = 0;
+        for (i, (offset, length)) in cuts.iter().enumerate() {
+            if i + 1 == cuts.len() && !final_pass {
+                break;
+            }
+            self.pending.push(ChunkWithData {
+                data: self.buf[*offset..offset + length].to_vec(),
+            });
+            consumed = offset + length;
+        }
+        self.buf.drain(..consumed);
+    }
+
+    // drain every chunk boundary FastCDC is willing to commit to given what's been written so far.
+    pub(crate) fn get_pending_chunks(&mut self, out: &mut Vec<ChunkWithData>) {
+        self.cut(false);
+        out.append(&mut self.pending);
+    }
+
+    // flush whatever's left in the buffer as a final chunk, once there's no more input coming.
+    pub(crate) fn finish(&mut self) {
+        self.cut(true);
+    }
+}
+
+impl io::Write for FastCDCWrapper {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn chunk_lengths(data: &[u8], config: ChunkerConfig) -> Vec<usize> {
+        let mut fcdc = FastCDCWrapper::new(config);
+        fcdc.write_all(data).unwrap();
+        fcdc.finish();
+        let mut chunks = Vec::new();
+        fcdc.get_pending_chunks(&mut chunks);
+        chunks.iter().map(|c| c.data.len()).collect()
+    }
+
+    #[test]
+    fn test_chunker_config_changes_chunk_count() {
+        // deterministic, but varied enough content that content-defined boundaries actually land
+        // in different places under different size targets.
+        let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let coarse = ChunkerConfig {
+            min_size: 64 * 1024,
+            avg_size: 128 * 1024,
+            max_size: 256 * 1024,
+            ..ChunkerConfig::default()
+        };
+        let fine = ChunkerConfig {
+            min_size: 2 * 1024,
+            avg_size: 4 * 1024,
+            max_size: 8 * 1024,
+            ..ChunkerConfig::default()
+        };
+
+        let coarse_chunks = chunk_lengths(&data, coarse);
+        let fine_chunks = chunk_lengths(&data, fine);
+
+        assert!(
+            fine_chunks.len() > coarse_chunks.len(),
+            "a smaller avg_size should cut more, smaller chunks: coarse={:?} fine={:?}",
+            coarse_chunks,
+            fine_chunks
+        );
+        assert_eq!(coarse_chunks.iter().sum::<usize>(), data.len());
+        assert_eq!(fine_chunks.iter().sum::<usize>(), data.len());
+    }
+}
@@ -0,0 +1,331 @@
+// a read-only FUSE server backed by a puzzlefs image. given a Rootfs (the set of metadata blobs
+// produced by build_initial_rootfs) and the Image it lives in, PuzzleFs serves it over the
+// standard Filesystem trait. the CBOR inode table is decoded in full, once, when PuzzleFs is
+// opened; directory entries, file chunk lists and symlink targets are decoded from the metadata
+// blob only when a request needs them.
+//
+// there are no open file handles yet: read() reopens the backing blob(s) on every call. that's
+// simple and correct, just not as fast as it could be; revisit if profiling says it matters.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+use nix::sys::stat::makedev;
+
+use format::{BlobRef, DirEnt, FileChunkList, Ino, Inode, InodeMode, Rootfs};
+use oci::Image;
+
+use crate::{Error, Result};
+
+// attributes are immutable for the lifetime of the mount, so there's no reason to ever ask the
+// kernel to revalidate them.
+const TTL: Duration = Duration::MAX;
+
+// fuse reserves inode 1 for the mount root. puzzlefs also numbers its root "/" as ino 1, so the
+// two line up without any translation for the common case; the tracker below exists so we're not
+// relying on that coincidence everywhere else.
+const FUSE_ROOT_INO: u64 = 1;
+
+// maps kernel-visible inode numbers to puzzlefs Inos (and back). populated on demand as inodes
+// are looked up, rather than all at once, since walking the whole tree up front defeats the
+// point of deserializing lazily.
+#[derive(Default)]
+struct InodeTracker {
+    to_pfs: HashMap<u64, Ino>,
+    to_kernel: HashMap<Ino, u64>,
+    next: u64,
+}
+
+impl InodeTracker {
+    fn new() -> Self {
+        let mut t = Self {
+            to_pfs: HashMap::new(),
+            to_kernel: HashMap::new(),
+            next: FUSE_ROOT_INO + 1,
+        };
+        t.to_pfs.insert(FUSE_ROOT_INO, 1);
+        t.to_kernel.insert(1, FUSE_ROOT_INO);
+        t
+    }
+
+    fn kernel_ino(&mut self, pfs_ino: Ino) -> u64 {
+        if let Some(k) = self.to_kernel.get(&pfs_ino) {
+            return *k;
+        }
+        let k = self.next;
+        self.next += 1;
+        self.to_kernel.insert(pfs_ino, k);
+        self.to_pfs.insert(k, pfs_ino);
+        k
+    }
+
+    fn pfs_ino(&self, kernel_ino: u64) -> Option<Ino> {
+        self.to_pfs.get(&kernel_ino).copied()
+    }
+}
+
+fn enoent() -> Error {
+    io::Error::from(io::ErrorKind::NotFound).into()
+}
+
+// the fuser FileType a puzzlefs inode should be reported as. shared by attr_for and readdir so the
+// two can't drift apart on how a given mode is labeled.
+fn file_type_for(mode: &InodeMode) -> FileType {
+    match mode {
+        InodeMode::Dir { .. } => FileType::Directory,
+        InodeMode::Reg { .. } => FileType::RegularFile,
+        InodeMode::Lnk { .. } => FileType::Symlink,
+        InodeMode::Char { .. } => FileType::CharDevice,
+        InodeMode::Block { .. } => FileType::BlockDevice,
+        InodeMode::Fifo => FileType::NamedPipe,
+        InodeMode::Socket => FileType::Socket,
+    }
+}
+
+// mount `rootfs` read-only at `mountpoint`, blocking until it's unmounted.
+pub fn mount(rootfs: &Rootfs, oci: Image, mountpoint: &Path) -> Result<()> {
+    let fs = PuzzleFs::open(rootfs, oci)?;
+    let opts = [MountOption::RO, MountOption::FSName("puzzlefs".to_string())];
+    fuser::mount2(fs, mountpoint, &opts)?;
+    Ok(())
+}
+
+pub struct PuzzleFs {
+    oci: Image,
+    metadata: BlobRef,
+    inodes: Vec<Inode>,
+    by_ino: HashMap<Ino, usize>,
+    tracker: InodeTracker,
+    // pfs_ino of a directory -> pfs_ino of its parent, so readdir can answer "..". populated
+    // lazily as directories are listed, mirroring the tracker above; the root is its own parent.
+    parents: HashMap<Ino, Ino>,
+}
+
+impl PuzzleFs {
+    pub fn open(rootfs: &Rootfs, oci: Image) -> Result<Self> {
+        // TODO: support more than one metadata blob (layered/delta images)
+        let metadata = rootfs.metadatas[0].clone();
+        let inodes: Vec<Inode> = serde_cbor::from_reader(oci.open_blob(&metadata)?)?;
+        let by_ino = inodes.iter().enumerate().map(|(i, n)| (n.ino, i)).collect();
+        let mut parents = HashMap::new();
+        parents.insert(1, 1);
+        Ok(Self {
+            oci,
+            metadata,
+            inodes,
+            by_ino,
+            tracker: InodeTracker::new(),
+            parents,
+        })
+    }
+
+    fn inode(&self, ino: Ino) -> Result<&Inode> {
+        self.by_ino
+            .get(&ino)
+            .map(|i| &self.inodes[*i])
+            .ok_or_else(enoent)
+    }
+
+    // deserialize a CBOR value stored at `offset` inside the metadata blob.
+    fn read_local<T: serde::de::DeserializeOwned>(&self, offset: u64) -> Result<T> {
+        let mut blob = self.oci.open_blob(&self.metadata)?;
+        blob.seek(SeekFrom::Start(offset))?;
+        Ok(serde_cbor::from_reader(blob)?)
+    }
+
+    fn dir_entries(&self, inode: &Inode) -> Result<Vec<DirEnt>> {
+        match inode.mode {
+            InodeMode::Dir { offset } => self.read_local(offset),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput).into()),
+        }
+    }
+
+    fn file_chunks(&self, inode: &Inode) -> Result<FileChunkList> {
+        match inode.mode {
+            InodeMode::Reg { offset } => self.read_local(offset),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput).into()),
+        }
+    }
+
+    fn symlink_target(&self, inode: &Inode) -> Result<PathBuf> {
+        match inode.mode {
+            InodeMode::Lnk { offset } => self.read_local(offset),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput).into()),
+        }
+    }
+
+    fn attr_for(&mut self, inode: &Inode) -> FileAttr {
+        let kind = file_type_for(&inode.mode);
+        // makedev(0, 0) for anything that isn't a device node, same as extract.rs's mknod_at
+        // leaves non-device nodes alone.
+        let rdev = match inode.mode {
+            InodeMode::Char { major, minor } | InodeMode::Block { major, minor } => {
+                makedev(major as u64, minor as u64) as u32
+            }
+            _ => 0,
+        };
+        FileAttr {
+            ino: self.tracker.kernel_ino(inode.ino),
+            size: inode.file_len,
+            blocks: (inode.file_len + 511) / 512,
+            atime: std::time::UNIX_EPOCH,
+            mtime: std::time::UNIX_EPOCH,
+            ctime: std::time::UNIX_EPOCH,
+            crtime: std::time::UNIX_EPOCH,
+            kind,
+            perm: inode.permissions,
+            nlink: 1,
+            uid: inode.uid,
+            gid: inode.gid,
+            rdev,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for PuzzleFs {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let result = (|| -> Result<FileAttr> {
+            let parent_ino = self.tracker.pfs_ino(parent).ok_or_else(enoent)?;
+            let parent_inode = self.inode(parent_ino)?;
+            let dirent = self
+                .dir_entries(parent_inode)?
+                .into_iter()
+                .find(|e| e.name == name)
+                .ok_or_else(enoent)?;
+            let child = self.inode(dirent.ino)?.clone();
+            Ok(self.attr_for(&child))
+        })();
+
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(_) => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let result = (|| -> Result<FileAttr> {
+            let pfs_ino = self.tracker.pfs_ino(ino).ok_or_else(enoent)?;
+            let inode = self.inode(pfs_ino)?.clone();
+            Ok(self.attr_for(&inode))
+        })();
+
+        match result {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(_) => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let result = (|| -> Result<PathBuf> {
+            let pfs_ino = self.tracker.pfs_ino(ino).ok_or_else(enoent)?;
+            let inode = self.inode(pfs_ino)?;
+            self.symlink_target(inode)
+        })();
+
+        match result {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(_) => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let result = (|| -> Result<(Ino, Vec<DirEnt>)> {
+            let pfs_ino = self.tracker.pfs_ino(ino).ok_or_else(enoent)?;
+            let inode = self.inode(pfs_ino)?;
+            Ok((pfs_ino, self.dir_entries(inode)?))
+        })();
+
+        let (pfs_ino, entries) = match result {
+            Ok(v) => v,
+            Err(_) => return reply.error(libc::ENOENT),
+        };
+
+        let parent_pfs_ino = self.parents.get(&pfs_ino).copied().unwrap_or(pfs_ino);
+        let parent_kernel_ino = self.tracker.kernel_ino(parent_pfs_ino);
+
+        // "." and ".." first, then the directory's real children, all numbered in one sequence so
+        // `offset` (the fuse "resume after this entry" cursor) stays meaningful across calls.
+        let mut listing: Vec<(u64, FileType, OsString)> = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (parent_kernel_ino, FileType::Directory, OsString::from("..")),
+        ];
+        for entry in entries {
+            let inode = match self.inode(entry.ino) {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            if matches!(inode.mode, InodeMode::Dir { .. }) {
+                self.parents.entry(entry.ino).or_insert(pfs_ino);
+            }
+            let kind = file_type_for(&inode.mode);
+            let kernel_ino = self.tracker.kernel_ino(entry.ino);
+            listing.push((kernel_ino, kind, entry.name));
+        }
+
+        for (i, (kernel_ino, kind, name)) in listing.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(kernel_ino, (i + 1) as i64, kind, &name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_file(ino, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(_) => reply.error(libc::EIO),
+        }
+    }
+}
+
+impl PuzzleFs {
+    fn read_file(&self, ino: u64, offset: u64, size: usize) -> Result<Vec<u8>> {
+        let pfs_ino = self.tracker.pfs_ino(ino).ok_or_else(enoent)?;
+        let inode = self.inode(pfs_ino)?;
+        let chunks = self.file_chunks(inode)?;
+
+        let mut out = Vec::with_capacity(size);
+        let want_end = offset + size as u64;
+        let mut pos = 0u64;
+        for chunk in chunks.chunks {
+            let chunk_end = pos + chunk.len;
+            if chunk_end > offset && pos < want_end {
+                let skip = offset.saturating_sub(pos);
+                let take = chunk_end.min(want_end) - (pos + skip);
+                out.extend(crate::read_chunk(&self.oci, &chunk.blob, skip, take)?);
+            }
+            pos = chunk_end;
+            if pos >= want_end {
+                break;
+            }
+        }
+        Ok(out)
+    }
+}
@@ -5,20 +5,38 @@ extern crate assert_matches;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Seek};
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 
 use walkdir::WalkDir;
 
+// NOTE: per-blob compression (Compression, and the `compression`/`uncompressed_len` fields on
+// BlobRef below) depends on a format-crate change that isn't part of this series: the
+// `format` crate lives outside this repo/checkout, so its diff can't be included here for review
+// alongside the builder-side change that consumes it. For the record, what this series assumes
+// `format` now looks like:
+//   - a new `pub enum Compression { None, Zstd }` with `encode`/`decode` methods, used as the
+//     codec tag for a stored blob;
+//   - `BlobRef` gaining `pub compression: Compression` and `pub uncompressed_len: u64` fields,
+//     so a reader can tell how a stored blob was written and how large it is once decoded
+//     without re-deriving either from the blob itself. `FileChunk` keeps just `blob` and `len`
+//     and reaches both through its `blob`.
+// Whoever owns the format crate should review that change together with this one before either
+// merges.
 use format::{
-    BlobRef, BlobRefKind, DirEnt, FileChunk, FileChunkList, Ino, Inode, InodeAdditional, Rootfs,
+    BlobRef, BlobRefKind, Compression, DirEnt, FileChunk, FileChunkList, Ino, Inode,
+    InodeAdditional, InodeMode, Rootfs,
 };
 use oci::Image;
 
 mod fastcdc_fs;
 use fastcdc_fs::{ChunkWithData, FastCDCWrapper};
 
+pub mod extract;
+pub mod fuse;
+pub mod verify;
+
 #[derive(Debug)]
 pub struct Error {
     msg: String,
@@ -90,26 +108,162 @@ struct File {
     additional: Option<InodeAdditional>,
 }
 
-fn write_chunks_to_oci(oci: &Image, fcdc: &mut FastCDCWrapper) -> io::Result<Vec<FileChunk>> {
+// counts of new vs. reused chunks written by a single build, so callers of build_delta_rootfs can
+// tell how effective dedup against the parent was.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DedupStats {
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+    pub reused_chunks: u64,
+    pub reused_bytes: u64,
+}
+
+// a known chunk, as seen in a parent image's metadata: the digest its backing blob is actually
+// stored under, its logical (uncompressed) length, and the codec it was written with, so a delta
+// build can point at it without having to guess how to inflate it later.
+#[derive(Debug, Clone, Copy)]
+struct KnownChunk {
+    stored_digest: format::Digest,
+    len: u64,
+    compression: Compression,
+}
+
+// content (uncompressed) digest -> info for every chunk already reachable from a parent image's
+// metadata, so a delta build can skip re-writing blobs it can already reference.
+//
+// this is keyed by content digest, not by the digest a chunk's blob is stored under: a stored
+// digest is taken over whatever compression wrote to disk (see write_chunks_to_oci), so two
+// parents that chose different compression for the same bytes would never collide on it. hashing
+// each unique stored blob's decoded content here, once, makes the lookup key line up with the one
+// write_chunks_to_oci computes for a candidate chunk regardless of which codec either side used.
+fn parent_chunk_digests(oci: &Image, parent: &Rootfs) -> Result<HashMap<format::Digest, KnownChunk>> {
+    let mut stored_blobs = HashMap::<format::Digest, BlobRef>::new();
+    for metadata in &parent.metadatas {
+        let inodes: Vec<Inode> = serde_cbor::from_reader(oci.open_blob(metadata)?)?;
+        for inode in &inodes {
+            if let InodeMode::Reg { offset } = inode.mode {
+                let mut blob = oci.open_blob(metadata)?;
+                blob.seek(io::SeekFrom::Start(offset))?;
+                let chunks: FileChunkList = serde_cbor::from_reader(blob)?;
+                for chunk in chunks.chunks {
+                    if let BlobRefKind::Other { digest } = chunk.blob.kind {
+                        // every per-file chunk that shares this stored digest carries the same
+                        // compression/uncompressed_len, so the first one we see is as good as any.
+                        stored_blobs.entry(digest).or_insert(chunk.blob);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut known = HashMap::new();
+    for (stored_digest, blob) in stored_blobs {
+        let whole_blob = BlobRef {
+            offset: 0,
+            kind: blob.kind,
+            compression: blob.compression,
+            uncompressed_len: blob.uncompressed_len,
+        };
+        let raw = read_chunk(oci, &whole_blob, 0, blob.uncompressed_len)?;
+        let content_digest = oci.digest(&raw);
+        known.insert(
+            content_digest,
+            KnownChunk {
+                stored_digest,
+                len: blob.uncompressed_len,
+                compression: blob.compression,
+            },
+        );
+    }
+    Ok(known)
+}
+
+fn write_chunks_to_oci(
+    oci: &Image,
+    fcdc: &mut FastCDCWrapper,
+    known_chunks: &HashMap<format::Digest, KnownChunk>,
+    stats: &mut DedupStats,
+    compression: Compression,
+) -> io::Result<Vec<FileChunk>> {
     let mut pending_chunks = Vec::<ChunkWithData>::new();
     fcdc.get_pending_chunks(&mut pending_chunks);
     pending_chunks
         .iter_mut()
         .map(|c| {
-            let desc = oci.put_blob(&*c.data)?;
+            // hash the chunk's logical content, the same way parent_chunk_digests derives its
+            // lookup key -- not the compressed bytes we're about to write, which would only ever
+            // match a parent that happened to pick the same codec.
+            let digest = oci.digest(&*c.data);
+            if let Some(known) = known_chunks.get(&digest) {
+                stats.reused_chunks += 1;
+                stats.reused_bytes += known.len;
+                return Ok(FileChunk {
+                    blob: BlobRef {
+                        kind: BlobRefKind::Other {
+                            digest: known.stored_digest,
+                        },
+                        offset: 0,
+                        compression: known.compression,
+                        uncompressed_len: known.len,
+                    },
+                    len: known.len,
+                });
+            }
+
+            // merged chunks (several small files packed together) are compressed as a single
+            // unit; offset/len throughout the rest of the pipeline stay logical (uncompressed),
+            // so only the bytes handed to put_blob are affected.
+            let uncompressed_len = c.data.len() as u64;
+            let on_disk = compression.encode(&c.data)?;
+            let desc = oci.put_blob(&on_disk)?;
+            stats.new_chunks += 1;
+            // logical (uncompressed) bytes, matching reused_bytes above -- otherwise a caller
+            // computing dedup effectiveness (reused_bytes / (new_bytes + reused_bytes)) mixes
+            // compressed and uncompressed units and the ratio is meaningless once compression
+            // is in play.
+            stats.new_bytes += uncompressed_len;
             Ok(FileChunk {
                 blob: BlobRef {
                     kind: BlobRefKind::Other {
                         digest: desc.digest,
                     },
                     offset: 0,
+                    compression,
+                    uncompressed_len,
                 },
-                len: desc.len,
+                len: uncompressed_len,
             })
         })
         .collect::<io::Result<Vec<FileChunk>>>()
 }
 
+// read `take` logical (uncompressed) bytes starting at logical offset `blob.offset + skip` from
+// the blob `blob` points at. FileChunk.blob.offset/len are always in uncompressed bytes -- a
+// merged chunk packs several small files together -- so a compressed blob has to be inflated in
+// full before the requested range can be sliced out of it.
+pub(crate) fn read_chunk(oci: &Image, blob: &BlobRef, skip: u64, take: u64) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let start = (blob.offset + skip) as usize;
+    let end = start + take as usize;
+
+    match blob.compression {
+        Compression::None => {
+            let mut reader = oci.open_blob(blob)?;
+            reader.seek(io::SeekFrom::Start(start as u64))?;
+            let mut buf = vec![0u8; take as usize];
+            reader.read_exact(&mut buf)?;
+            Ok(buf)
+        }
+        compression => {
+            let mut raw = Vec::new();
+            oci.open_blob(blob)?.read_to_end(&mut raw)?;
+            let inflated = compression.decode(&raw, blob.uncompressed_len as usize)?;
+            Ok(inflated[start..end].to_vec())
+        }
+    }
+}
+
 // merge the first chunk with the previous files and return a BlobRef that references the rest of
 // the file
 fn merge_chunk_and_prev_files(
@@ -118,12 +272,14 @@ fn merge_chunk_and_prev_files(
     prev_files: &mut Vec<File>,
 ) -> io::Result<BlobRef> {
     let mut used = 0;
-    let first_digest = if let BlobRef {
+    let (first_digest, compression, uncompressed_len) = if let BlobRef {
         kind: BlobRefKind::Other { digest },
+        compression,
+        uncompressed_len,
         ..
     } = first_chunk.blob
     {
-        digest
+        (digest, compression, uncompressed_len)
     } else {
         return Err(io::Error::new(io::ErrorKind::Other, "bad blob type"));
     };
@@ -135,6 +291,8 @@ fn merge_chunk_and_prev_files(
             kind: BlobRefKind::Other {
                 digest: first_digest,
             },
+            compression,
+            uncompressed_len,
         };
         let len = p.md.len();
         used += len;
@@ -148,6 +306,8 @@ fn merge_chunk_and_prev_files(
             digest: first_digest,
         },
         offset: used,
+        compression,
+        uncompressed_len,
     })
 }
 
@@ -155,7 +315,98 @@ fn inode_encoded_size(num_inodes: usize) -> usize {
     format::cbor_size_of_list_header(num_inodes) + num_inodes * format::INODE_WIRE_SIZE
 }
 
-pub fn build_initial_rootfs(rootfs: &Path, oci: &Image) -> Result<Rootfs> {
+// which content-defined chunking algorithm to split files with. FastCDC is the only one
+// implemented today; Fixed is reserved for workloads (e.g. already-compressed data) where
+// content-defined boundaries buy nothing over plain fixed-size "rolling off" splitting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkerAlgorithm {
+    FastCDC,
+    Fixed,
+}
+
+// tunable content-defined chunking knobs. large-file-heavy workloads want a bigger avg_size
+// (less metadata per byte of content); many-small-files workloads want a smaller one (finer
+// grained dedup). sizes are in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkerConfig {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+    pub algorithm: ChunkerAlgorithm,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 4 * 1024,
+            avg_size: 16 * 1024,
+            max_size: 64 * 1024,
+            algorithm: ChunkerAlgorithm::FastCDC,
+        }
+    }
+}
+
+impl ChunkerConfig {
+    fn validate(&self) -> Result<()> {
+        if self.algorithm != ChunkerAlgorithm::FastCDC {
+            return Err(Error {
+                msg: "only the FastCDC chunking algorithm is implemented".to_string(),
+            });
+        }
+        if !(self.min_size <= self.avg_size && self.avg_size <= self.max_size) {
+            return Err(Error {
+                msg: format!(
+                    "invalid chunker config: min ({}) <= avg ({}) <= max ({}) must hold",
+                    self.min_size, self.avg_size, self.max_size
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+// knobs that affect how a build lays out chunks/blobs on disk, bundled together since they tend
+// to get threaded through build_initial_rootfs/build_delta_rootfs together.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuildOptions {
+    pub compression: Compression,
+    pub chunker: ChunkerConfig,
+}
+
+pub fn build_initial_rootfs(rootfs: &Path, oci: &Image, opts: BuildOptions) -> Result<Rootfs> {
+    build_rootfs(
+        rootfs,
+        oci,
+        &HashMap::new(),
+        &mut DedupStats::default(),
+        &opts,
+    )
+}
+
+// like build_initial_rootfs, but chunks already reachable from `parent`'s metadata are referenced
+// instead of rewritten, so a second image layered on `parent` only pays for what actually
+// changed. returns the new Rootfs plus stats on how much was reused.
+pub fn build_delta_rootfs(
+    rootfs: &Path,
+    oci: &Image,
+    parent: &Rootfs,
+    opts: BuildOptions,
+) -> Result<(Rootfs, DedupStats)> {
+    let known_chunks = parent_chunk_digests(oci, parent)?;
+    let mut stats = DedupStats::default();
+    let built = build_rootfs(rootfs, oci, &known_chunks, &mut stats, &opts)?;
+    Ok((built, stats))
+}
+
+fn build_rootfs(
+    rootfs: &Path,
+    oci: &Image,
+    known_chunks: &HashMap<format::Digest, KnownChunk>,
+    stats: &mut DedupStats,
+    opts: &BuildOptions,
+) -> Result<Rootfs> {
+    opts.chunker.validate()?;
+
     let mut dirs = HashMap::<u64, Dir>::new();
     let mut files = Vec::<File>::new();
     let mut pfs_inodes = Vec::<Inode>::new();
@@ -165,7 +416,7 @@ pub fn build_initial_rootfs(rootfs: &Path, oci: &Image) -> Result<Rootfs> {
 
     let mut cur_ino: u64 = 1;
 
-    let mut fcdc = FastCDCWrapper::new();
+    let mut fcdc = FastCDCWrapper::new(opts.chunker);
     let mut prev_files = Vec::<File>::new();
 
     for entry in walker(rootfs) {
@@ -218,7 +469,7 @@ pub fn build_initial_rootfs(rootfs: &Path, oci: &Image) -> Result<Rootfs> {
             let mut f = fs::File::open(e.path())?;
             io::copy(&mut f, &mut fcdc)?;
 
-            let mut written_chunks = write_chunks_to_oci(&oci, &mut fcdc)?;
+            let mut written_chunks = write_chunks_to_oci(oci, &mut fcdc, known_chunks, stats, opts.compression)?;
             let mut file = File {
                 ino: cur_ino,
                 md,
@@ -254,7 +505,7 @@ pub fn build_initial_rootfs(rootfs: &Path, oci: &Image) -> Result<Rootfs> {
 
     // all inodes done, we need to finish up the cdc chunking
     fcdc.finish();
-    let written_chunks = write_chunks_to_oci(&oci, &mut fcdc)?;
+    let written_chunks = write_chunks_to_oci(oci, &mut fcdc, known_chunks, stats, opts.compression)?;
     let leftover: u64 = written_chunks.iter().map(|c| c.len).sum();
 
     // if we have chunks, we should have files too
@@ -297,6 +548,8 @@ pub fn build_initial_rootfs(rootfs: &Path, oci: &Image) -> Result<Rootfs> {
                         Ok(BlobRef {
                             offset: offset as u64,
                             kind: BlobRefKind::Local,
+                            compression: Compression::None,
+                            uncompressed_len: 0,
                         })
                     })
                     .transpose()?;
@@ -328,6 +581,8 @@ pub fn build_initial_rootfs(rootfs: &Path, oci: &Image) -> Result<Rootfs> {
                         Ok(BlobRef {
                             offset: offset as u64,
                             kind: BlobRefKind::Local,
+                            compression: Compression::None,
+                            uncompressed_len: 0,
                         })
                     })
                     .transpose()?;
@@ -349,12 +604,15 @@ pub fn build_initial_rootfs(rootfs: &Path, oci: &Image) -> Result<Rootfs> {
     md_buf.append(&mut dir_buf);
     md_buf.append(&mut files_buf);
 
+    let metadata_len = md_buf.len() as u64;
     let desc = oci.put_blob(md_buf.as_slice())?;
     let metadatas = [BlobRef {
         offset: 0,
         kind: BlobRefKind::Other {
             digest: desc.digest,
         },
+        compression: Compression::None,
+        uncompressed_len: metadata_len,
     }]
     .to_vec();
     Ok(Rootfs { metadatas })
@@ -379,7 +637,8 @@ mod tests {
         // test...
         //
         // but once all that's stabalized, we should verify the metadata hash too.
-        let rootfs = build_initial_rootfs(Path::new("./test"), &image).unwrap();
+        let rootfs =
+            build_initial_rootfs(Path::new("./test"), &image, BuildOptions::default()).unwrap();
 
         // there should be a blob that matches the hash of the test data, since it all gets input
         // as one chunk and there's only one file
@@ -410,4 +669,49 @@ mod tests {
         assert_eq!(inodes[1].uid, md.uid());
         assert_eq!(inodes[1].gid, md.gid());
     }
+
+    #[test]
+    fn test_delta_dedup_survives_compression() {
+        // a delta build against its own parent should reuse every chunk, even when compression is
+        // on -- the dedup lookup hashes logical content, not the codec-dependent bytes put_blob
+        // actually stores, so this must hold regardless of which compression is configured.
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+
+        let opts = BuildOptions {
+            compression: Compression::Zstd,
+            ..BuildOptions::default()
+        };
+
+        let parent = build_initial_rootfs(Path::new("./test"), &image, opts).unwrap();
+        let (_delta, stats) = build_delta_rootfs(Path::new("./test"), &image, &parent, opts).unwrap();
+
+        assert_eq!(stats.new_chunks, 0);
+        assert!(stats.reused_chunks > 0);
+    }
+
+    #[test]
+    fn test_chunker_config_validate() {
+        ChunkerConfig::default().validate().unwrap();
+
+        let min_above_avg = ChunkerConfig {
+            min_size: 32 * 1024,
+            avg_size: 16 * 1024,
+            ..ChunkerConfig::default()
+        };
+        assert!(min_above_avg.validate().is_err());
+
+        let avg_above_max = ChunkerConfig {
+            avg_size: 128 * 1024,
+            max_size: 64 * 1024,
+            ..ChunkerConfig::default()
+        };
+        assert!(avg_above_max.validate().is_err());
+
+        let fixed = ChunkerConfig {
+            algorithm: ChunkerAlgorithm::Fixed,
+            ..ChunkerConfig::default()
+        };
+        assert!(fixed.validate().is_err());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,312 @@
+// an fsck-style pass over a built Rootfs: confirms every reference the inode table makes into
+// the OCI store actually resolves, without panicking on the first thing that's wrong. shallow
+// mode only chases pointers; full mode additionally recomputes digests and checks that a file's
+// chunks tile their backing blobs with no gaps or overlaps, mirroring the invariant
+// merge_chunk_and_prev_files maintains at build time.
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+use format::{BlobRef, BlobRefKind, Compression, Digest, FileChunkList, Inode, InodeMode, Rootfs};
+use oci::Image;
+
+use crate::Result;
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub dangling_refs: Vec<String>,
+    pub digest_mismatches: Vec<String>,
+    pub chunk_gaps: Vec<String>,
+    pub chunk_overlaps: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.dangling_refs.is_empty()
+            && self.digest_mismatches.is_empty()
+            && self.chunk_gaps.is_empty()
+            && self.chunk_overlaps.is_empty()
+    }
+}
+
+// every chunk of every file that claims to live in a given blob, used to check the blob is
+// tiled without gaps or overlaps (full mode only).
+struct BlobCoverage {
+    uncompressed_len: u64,
+    intervals: Vec<(u64, u64)>,
+}
+
+pub fn verify(rootfs: &Rootfs, oci: &Image, full: bool) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut coverage = HashMap::<Digest, BlobCoverage>::new();
+
+    for metadata in &rootfs.metadatas {
+        let metadata_blob = match oci.open_blob(metadata) {
+            Ok(b) => b,
+            Err(e) => {
+                report
+                    .dangling_refs
+                    .push(format!("metadata blob is unreadable: {}", e));
+                continue;
+            }
+        };
+        let metadata_len = blob_len(oci, metadata, &mut report);
+
+        let inodes: Vec<Inode> = match serde_cbor::from_reader(metadata_blob) {
+            Ok(inodes) => inodes,
+            Err(e) => {
+                report
+                    .dangling_refs
+                    .push(format!("metadata blob inode table is undecodable: {}", e));
+                continue;
+            }
+        };
+
+        for inode in &inodes {
+            check_additional(inode, metadata_len, &mut report);
+
+            let offset = match inode.mode {
+                InodeMode::Dir { offset } => offset,
+                InodeMode::Reg { offset } => offset,
+                InodeMode::Lnk { offset } => offset,
+                _ => continue,
+            };
+            if let Some(len) = metadata_len {
+                if offset >= len {
+                    report.dangling_refs.push(format!(
+                        "inode {}: local offset {} is outside metadata blob ({} bytes)",
+                        inode.ino, offset, len
+                    ));
+                    continue;
+                }
+            }
+
+            if let InodeMode::Reg { .. } = inode.mode {
+                let mut r = match oci.open_blob(metadata) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        report.dangling_refs.push(format!(
+                            "inode {}: could not reopen metadata blob: {}",
+                            inode.ino, e
+                        ));
+                        continue;
+                    }
+                };
+                if let Err(e) = r.seek(SeekFrom::Start(offset)) {
+                    report.dangling_refs.push(format!(
+                        "inode {}: could not seek metadata blob: {}",
+                        inode.ino, e
+                    ));
+                    continue;
+                }
+                let chunks: FileChunkList = match serde_cbor::from_reader(r) {
+                    Ok(chunks) => chunks,
+                    Err(e) => {
+                        report.dangling_refs.push(format!(
+                            "inode {}: file chunk list is undecodable: {}",
+                            inode.ino, e
+                        ));
+                        continue;
+                    }
+                };
+
+                for chunk in &chunks.chunks {
+                    let digest = match chunk.blob.kind {
+                        BlobRefKind::Other { digest } => digest,
+                        BlobRefKind::Local => {
+                            report.dangling_refs.push(format!(
+                                "inode {}: file chunk unexpectedly points at the metadata blob",
+                                inode.ino
+                            ));
+                            continue;
+                        }
+                    };
+
+                    if full {
+                        coverage
+                            .entry(digest)
+                            .or_insert_with(|| BlobCoverage {
+                                uncompressed_len: chunk.blob.uncompressed_len,
+                                intervals: Vec::new(),
+                            })
+                            .intervals
+                            .push((chunk.blob.offset, chunk.len));
+                    } else if oci.open_blob(&chunk.blob).is_err() {
+                        report.dangling_refs.push(format!(
+                            "inode {}: chunk blob {:?} does not exist",
+                            inode.ino, digest
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    if full {
+        for (digest, cov) in coverage {
+            check_digest(oci, digest, &mut report);
+            check_tiling(digest, &cov, &mut report);
+        }
+    }
+
+    Ok(report)
+}
+
+// inode.additional (device major/minor, xattrs, ...) is stored the same way a dir's entries or a
+// file's chunk list are: a Local BlobRef pointing back into this same metadata blob. build_rootfs
+// never emits anything else for it, so anything else here is as dangling as an out-of-range
+// offset.
+fn check_additional(inode: &Inode, metadata_len: Option<u64>, report: &mut VerifyReport) {
+    let Some(additional) = &inode.additional else {
+        return;
+    };
+
+    if !matches!(additional.kind, BlobRefKind::Local) {
+        report.dangling_refs.push(format!(
+            "inode {}: additional metadata unexpectedly points outside the metadata blob",
+            inode.ino
+        ));
+        return;
+    }
+
+    if let Some(len) = metadata_len {
+        if additional.offset >= len {
+            report.dangling_refs.push(format!(
+                "inode {}: additional metadata offset {} is outside metadata blob ({} bytes)",
+                inode.ino, additional.offset, len
+            ));
+        }
+    }
+}
+
+fn blob_len(oci: &Image, blobref: &BlobRef, report: &mut VerifyReport) -> Option<u64> {
+    match oci.open_blob(blobref).and_then(|mut b| {
+        let mut buf = Vec::new();
+        b.read_to_end(&mut buf)?;
+        Ok(buf.len() as u64)
+    }) {
+        Ok(len) => Some(len),
+        Err(e) => {
+            report
+                .dangling_refs
+                .push(format!("could not size metadata blob: {}", e));
+            None
+        }
+    }
+}
+
+fn check_digest(oci: &Image, digest: Digest, report: &mut VerifyReport) {
+    let blobref = BlobRef {
+        offset: 0,
+        kind: BlobRefKind::Other { digest },
+        compression: Compression::None,
+        uncompressed_len: 0,
+    };
+    let raw = match oci.open_blob(&blobref).and_then(|mut b| {
+        let mut buf = Vec::new();
+        b.read_to_end(&mut buf)?;
+        Ok(buf)
+    }) {
+        Ok(raw) => raw,
+        Err(e) => {
+            report
+                .dangling_refs
+                .push(format!("chunk blob {:?} does not exist: {}", digest, e));
+            return;
+        }
+    };
+    let actual = oci.digest(&raw);
+    if actual != digest {
+        report.digest_mismatches.push(format!(
+            "blob claims to be {:?} but its contents hash to {:?}",
+            digest, actual
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use crate::{build_initial_rootfs, BuildOptions};
+
+    fn metadata_digest(rootfs: &Rootfs) -> Digest {
+        match rootfs.metadatas[0].kind {
+            BlobRefKind::Other { digest } => digest,
+            BlobRefKind::Local => panic!("metadata blob must be content-addressed"),
+        }
+    }
+
+    #[test]
+    fn test_verify_reports_undecodable_blob_instead_of_erroring() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let rootfs =
+            build_initial_rootfs(Path::new("./test"), &image, BuildOptions::default()).unwrap();
+
+        // truncate the metadata blob to garbage that doesn't even parse as CBOR.
+        let path = image
+            .blob_path()
+            .join(format!("{:?}", metadata_digest(&rootfs)));
+        fs::write(&path, b"not cbor").unwrap();
+
+        let report = verify(&rootfs, &image, false)
+            .expect("a corrupted blob should be a report entry, not a hard error");
+        assert!(report
+            .dangling_refs
+            .iter()
+            .any(|r| r.contains("undecodable")));
+    }
+
+    #[test]
+    fn test_verify_catches_tampered_chunk_content() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let rootfs =
+            build_initial_rootfs(Path::new("./test"), &image, BuildOptions::default()).unwrap();
+
+        // "./test" only ever renders one file chunk (see test_fs_generation in lib.rs), so
+        // whichever stored blob isn't the metadata blob has to be it.
+        let metadata_digest = format!("{:?}", metadata_digest(&rootfs));
+        for entry in fs::read_dir(image.blob_path()).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_name().to_str() != Some(metadata_digest.as_str()) {
+                fs::write(entry.path(), b"tampered").unwrap();
+            }
+        }
+
+        let report = verify(&rootfs, &image, true).unwrap();
+        assert!(!report.digest_mismatches.is_empty());
+    }
+}
+
+fn check_tiling(digest: Digest, cov: &BlobCoverage, report: &mut VerifyReport) {
+    let mut intervals = cov.intervals.clone();
+    intervals.sort_by_key(|&(offset, _)| offset);
+
+    let mut expected = 0u64;
+    for (offset, len) in intervals {
+        if offset < expected {
+            report.chunk_overlaps.push(format!(
+                "blob {:?}: chunk at {} overlaps the one ending at {}",
+                digest, offset, expected
+            ));
+        } else if offset > expected {
+            report.chunk_gaps.push(format!(
+                "blob {:?}: gap between {} and {}",
+                digest, expected, offset
+            ));
+        }
+        expected = expected.max(offset + len);
+    }
+    if expected != cov.uncompressed_len {
+        report.chunk_gaps.push(format!(
+            "blob {:?}: chunks cover {} bytes but the blob is {} bytes",
+            digest, expected, cov.uncompressed_len
+        ));
+    }
+}